@@ -0,0 +1,27 @@
+use serde::Deserialize;
+
+/// Power mode requested by a remote operator via the command topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PowerMode {
+    Normal,
+    LowPower,
+}
+
+/// A command received on `{user}/feeds/command`.
+///
+/// Payloads are JSON objects tagged by `type`, e.g. `{"type":"setInterval","ms":2000}`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Command {
+    SetInterval { ms: u32 },
+    ForceRead,
+    SetPowerMode { mode: PowerMode },
+}
+
+impl Command {
+    /// Parses a command from a raw MQTT payload.
+    pub fn parse(payload: &[u8]) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+}
@@ -1,11 +1,14 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{self, bail, Result};
 use embedded_hal::blocking::delay::DelayMs;
 use embedded_svc::wifi::Wifi;
 use embedded_svc::{
     mqtt::client::{
-        Event::Received, Publish, QoS,
+        Event, Event::Received, LwtConfiguration, Message, Publish, QoS,
     },
     wifi::{
         ClientConfiguration, ClientConnectionStatus, ClientIpStatus, ClientStatus, Configuration,
@@ -23,35 +26,139 @@ use esp_idf_svc::{
     mqtt::client::{EspMqttClient, MqttClientConfiguration},
     netif::EspNetifStack,
     nvs::EspDefaultNvs,
+    sntp::{EspSntp, SntpConf, SyncStatus},
     sysloop::EspSysLoopStack,
+    tls::X509,
     wifi::EspWifi,
 };
 use esp_idf_sys::*;
 
+use heapless::HistoryBuffer;
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use shtcx::{self, PowerMode};
+
+mod command;
+mod persistence;
+mod sensor;
+
+use command::Command;
+use sensor::EnvSensor;
 
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("test.mosquitto.org")]
     mqtt_host: &'static str,
+    #[default(1883)]
+    mqtt_port: u32,
     #[default("")]
     mqtt_user: &'static str,
     #[default("")]
     mqtt_pass: &'static str,
+    #[default(false)]
+    mqtt_tls: bool,
+    /// PEM-encoded CA certificate used to verify the broker when `mqtt_tls` is set.
+    /// Left empty to fall back to the ESP-IDF global CA store.
+    #[default("")]
+    mqtt_ca_cert: &'static str,
     #[default("")]
     wifi_ssid: &'static str,
     #[default("")]
     wifi_psk: &'static str,
+    #[default("pool.ntp.org")]
+    ntp_server: &'static str,
+    /// How often the sensor is sampled, in milliseconds. Ignored by sensors
+    /// that already block for their own fixed measurement period (see
+    /// `EnvSensor::self_paced`), e.g. the SCD41.
+    #[default(200)]
+    sample_interval_ms: u32,
+    /// How often the moving average is published, in milliseconds.
+    #[default(5000)]
+    publish_interval_ms: u32,
+    /// Number of samples averaged into each published measurement.
+    #[default(16)]
+    average_window: usize,
+    /// Which sensor is wired to the I2C bus: `"shtc3"` or `"scd41"`.
+    #[default("shtc3")]
+    sensor: &'static str,
 }
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MqttMeasurement {
+    /// Unix epoch seconds at the time of the reading, from the NTP-synced clock.
+    pub timestamp: u64,
     /// The measured temperature.
     pub temperature: f32,
     /// The measured humidity.
     pub humidity: f32,
+    /// CO2 concentration in ppm, present only when a CO2-capable sensor (e.g. SCD41) is active.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub co2: Option<f32>,
+}
+
+/// State shared between the MQTT callback and the sampling loop, updated as
+/// `Command`s arrive on the command topic.
+struct AppState {
+    sample_interval_ms: u32,
+    force_read: bool,
+    power_mode: command::PowerMode,
+    needs_replay: bool,
+    needs_online_announce: bool,
+}
+
+impl AppState {
+    fn new(sample_interval_ms: u32) -> Self {
+        Self {
+            sample_interval_ms,
+            force_read: false,
+            power_mode: command::PowerMode::Normal,
+            needs_replay: true,
+            needs_online_announce: true,
+        }
+    }
+
+    fn apply(&mut self, command: Command) {
+        match command {
+            Command::SetInterval { ms } => self.sample_interval_ms = ms,
+            Command::ForceRead => self.force_read = true,
+            Command::SetPowerMode { mode } => self.power_mode = mode,
+        }
+    }
+}
+
+/// Rejects readings that look like a sensor fault rather than a real
+/// measurement: values outside the sensor's rated range (values exactly at
+/// the range limits are accepted), or
+/// temperature and humidity both reading exactly zero, the classic signature
+/// of a stuck I2C bus. Bad readings are dropped before they reach the
+/// moving average so a single glitch doesn't skew the published value.
+fn is_plausible(reading: &sensor::Reading) -> bool {
+    if let Some(temperature) = reading.temperature {
+        if !(-40.0..=125.0).contains(&temperature) {
+            return false;
+        }
+    }
+    if let Some(humidity) = reading.humidity {
+        if !(0.0..=100.0).contains(&humidity) {
+            return false;
+        }
+    }
+    if let Some(co2) = reading.co2 {
+        if co2 <= 0.0 {
+            return false;
+        }
+    }
+    if reading.temperature == Some(0.0) && reading.humidity == Some(0.0) {
+        return false;
+    }
+    true
+}
+
+/// Arithmetic mean of a (possibly partially filled) history buffer.
+fn mean<const N: usize>(buffer: &HistoryBuffer<f32, N>) -> f32 {
+    if buffer.is_empty() {
+        return 0.0;
+    }
+    buffer.iter().sum::<f32>() / buffer.len() as f32
 }
 
 fn main() -> anyhow::Result<()> {
@@ -61,6 +168,8 @@ fn main() -> anyhow::Result<()> {
 
     let app_config = CONFIG;
 
+    persistence::mount()?;
+
     let peripherals = Peripherals::take().unwrap();
 
     let sda = peripherals.pins.gpio10;
@@ -72,10 +181,13 @@ fn main() -> anyhow::Result<()> {
         <MasterConfig as Default>::default().baudrate(400.kHz().into()),
     )?;
 
-    let mut sht = shtcx::shtc3(i2c);
-    let device_id = sht.device_identifier().unwrap();
+    let mut active_sensor: Box<dyn EnvSensor> = match app_config.sensor {
+        "scd41" => Box::new(sensor::Scd41Sensor::new(i2c)?),
+        "shtc3" => Box::new(sensor::Shtc3Sensor::new(i2c)),
+        other => bail!("Unknown sensor {:?}, expected \"shtc3\" or \"scd41\"", other),
+    };
 
-    info!("Device ID SHTC3: {}", device_id);
+    info!("Using sensor: {}", app_config.sensor);
 
     let netif_stack = Arc::new(EspNetifStack::new()?);
     let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
@@ -86,57 +198,194 @@ fn main() -> anyhow::Result<()> {
         default_nvs.clone(),
     )?;
 
+    let sntp = EspSntp::new(&SntpConf {
+        servers: [app_config.ntp_server],
+        ..Default::default()
+    })?;
+
+    info!("Synchronizing time over NTP");
+    let ntp_sync_timeout = Duration::from_secs(20);
+    let ntp_sync_started = Instant::now();
+    while sntp.get_sync_status() != SyncStatus::Completed {
+        if ntp_sync_started.elapsed() > ntp_sync_timeout {
+            warn!(
+                "NTP sync did not complete within {:?}, continuing with an unsynced clock",
+                ntp_sync_timeout
+            );
+            break;
+        }
+        FreeRtos.delay_ms(200u32);
+    }
+    if sntp.get_sync_status() == SyncStatus::Completed {
+        info!("Time synchronized");
+    }
+
+    let server_certificate = if app_config.mqtt_tls && !app_config.mqtt_ca_cert.is_empty() {
+        // `pem_until_nul` scans for the first NUL byte to bound the certificate;
+        // `cfg.toml` values aren't guaranteed to carry one, so append it here
+        // rather than relying on every deployment's TOML to remember to.
+        let mut pem = String::with_capacity(app_config.mqtt_ca_cert.len() + 1);
+        pem.push_str(app_config.mqtt_ca_cert);
+        pem.push('\0');
+        let pem: &'static str = Box::leak(pem.into_boxed_str());
+        Some(X509::pem_until_nul(pem.as_bytes()))
+    } else {
+        None
+    };
+
+    let status_topic = format!("{}/feeds/status", app_config.mqtt_user);
+
     let mqtt_config = MqttClientConfiguration {
         client_id: Some("esp-rust-board-shtc3-mqtt"),
         keep_alive_interval: Some(Duration::from_secs(120)),
+        use_global_ca_store: app_config.mqtt_tls && app_config.mqtt_ca_cert.is_empty(),
+        server_certificate,
+        lwt: Some(LwtConfiguration {
+            topic: &status_topic,
+            payload: b"offline",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }),
         ..Default::default()
     };
 
+    let scheme = if app_config.mqtt_tls { "mqtts" } else { "mqtt" };
     let broker_url = if !app_config.mqtt_user.is_empty() {
         format!(
-            "mqtt://{}:{}@{}",
-            app_config.mqtt_user, app_config.mqtt_pass, app_config.mqtt_host
+            "{}://{}:{}@{}:{}",
+            scheme, app_config.mqtt_user, app_config.mqtt_pass, app_config.mqtt_host, app_config.mqtt_port
         )
     } else {
-        format!("mqtt://{}", app_config.mqtt_host)
+        format!("{}://{}:{}", scheme, app_config.mqtt_host, app_config.mqtt_port)
     };
 
-    let mut client =
+    let state = Arc::new(Mutex::new(AppState::new(app_config.sample_interval_ms)));
+
+    let mut client = {
+        let state = state.clone();
         EspMqttClient::new(
             broker_url,
             &mqtt_config,
             move |message_event| match message_event {
-                Ok(Received(msg)) => info!("MQTT Message: {:?}", msg),
+                Ok(Received(msg)) => {
+                    info!("MQTT Message: {:?}", msg);
+                    match Command::parse(&msg.data()) {
+                        Ok(command) => {
+                            info!("Applying command: {:?}", command);
+                            state.lock().unwrap().apply(command);
+                        }
+                        Err(e) => warn!("Ignoring non-command payload: {}", e),
+                    }
+                }
+                Ok(Event::Connected(_)) => {
+                    info!("MQTT connected, flagging buffered measurements for replay");
+                    let mut state = state.lock().unwrap();
+                    state.needs_replay = true;
+                    state.needs_online_announce = true;
+                }
                 _ => warn!("Received from MQTT: {:?}", message_event),
             },
-        )?;
+        )?
+    };
+
+    client.subscribe(
+        &format!("{}/feeds/command", app_config.mqtt_user),
+        QoS::AtLeastOnce,
+    )?;
+
+    let measurement_topic = format!("{}/feeds/measurement", app_config.mqtt_user);
+
+    let mut temperature_history: HistoryBuffer<f32, { CONFIG.average_window }> =
+        HistoryBuffer::new();
+    let mut humidity_history: HistoryBuffer<f32, { CONFIG.average_window }> = HistoryBuffer::new();
+    let mut co2_history: HistoryBuffer<f32, { CONFIG.average_window }> = HistoryBuffer::new();
+    let mut last_published = Instant::now();
 
     loop {
-        sht.start_measurement(PowerMode::NormalMode).unwrap();
-        FreeRtos.delay_ms(100u32);
-        let measurement = sht.get_measurement_result().unwrap();
+        let (sample_interval_ms, force_read, power_mode, needs_replay, needs_online_announce) = {
+            let mut state = state.lock().unwrap();
+            let force_read = std::mem::take(&mut state.force_read);
+            let needs_replay = std::mem::take(&mut state.needs_replay);
+            let needs_online_announce = std::mem::take(&mut state.needs_online_announce);
+            (
+                state.sample_interval_ms,
+                force_read,
+                state.power_mode,
+                needs_replay,
+                needs_online_announce,
+            )
+        };
+
+        if needs_online_announce {
+            if let Err(e) = client.publish(&status_topic, QoS::AtLeastOnce, true, b"online") {
+                warn!("Failed to publish online status: {}", e);
+            }
+        }
+
+        if needs_replay {
+            persistence::drain(|buffered| {
+                let js = serde_json::to_string(buffered)?;
+                client.publish(&measurement_topic, QoS::AtMostOnce, false, js.as_bytes())?;
+                Ok(())
+            })
+            .unwrap_or_else(|e| warn!("Failed to replay buffered measurements: {}", e));
+        }
+
+        active_sensor.start_measurement(power_mode).unwrap();
+        let reading = active_sensor.read().unwrap();
 
         info!(
-            "TEMP: {} Â°C\nHUM: {:?} %\n",
-            measurement.temperature.as_degrees_celsius(),
-            measurement.humidity.as_percent(),
+            "TEMP: {:?} Â°C\nHUM: {:?} %\nCO2: {:?} ppm\n",
+            reading.temperature, reading.humidity, reading.co2
         );
 
-        let m = MqttMeasurement {
-            temperature: measurement.temperature.as_degrees_celsius(),
-            humidity: measurement.humidity.as_percent(),
-        };
+        if is_plausible(&reading) {
+            if let Some(temperature) = reading.temperature {
+                temperature_history.write(temperature);
+            }
+            if let Some(humidity) = reading.humidity {
+                humidity_history.write(humidity);
+            }
+            if let Some(co2) = reading.co2 {
+                co2_history.write(co2);
+            }
+        } else {
+            warn!("Discarding implausible reading: {:?}", reading);
+        }
+
+        let publish_due = force_read
+            || last_published.elapsed() >= Duration::from_millis(app_config.publish_interval_ms as u64);
+
+        if publish_due {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+
+            let m = MqttMeasurement {
+                timestamp,
+                temperature: mean(&temperature_history),
+                humidity: mean(&humidity_history),
+                co2: (!co2_history.is_empty()).then(|| mean(&co2_history)),
+            };
+
+            let js = serde_json::to_string(&m)?;
+            match client.publish(&measurement_topic, QoS::AtMostOnce, false, js.as_bytes()) {
+                Ok(_) => info!("Published message: {}", js),
+                Err(e) => {
+                    warn!("Publish failed ({}), buffering measurement to flash", e);
+                    if let Err(e) = persistence::append(&m) {
+                        warn!("Failed to buffer measurement: {}", e);
+                    }
+                }
+            }
 
-        let js = serde_json::to_string(&m)?;
-        client.publish(
-            &format!("{}/feeds/measurement", app_config.mqtt_user),
-            QoS::AtMostOnce,
-            false,
-            js.as_bytes(),
-        )?;
-        info!("Published message: {}", js);
+            last_published = Instant::now();
+        }
 
-        FreeRtos.delay_ms(500u32);
+        if !force_read && !active_sensor.self_paced() {
+            FreeRtos.delay_ms(sample_interval_ms);
+        }
     }
 }
 
@@ -0,0 +1,122 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+
+use esp_idf_sys::*;
+
+use crate::MqttMeasurement;
+
+const MOUNT_POINT: &str = "/spiflash";
+const PARTITION_LABEL: &str = "storage";
+const BUFFER_PATH: &str = "/spiflash/buffer.jsonl";
+const MAX_BUFFERED_RECORDS: usize = 256;
+
+/// Mounts the internal SPI flash as a FAT partition so measurements can
+/// survive a lost Wi-Fi/broker connection.
+///
+/// Must be called once at startup, before `append` or `drain` is used.
+pub fn mount() -> Result<()> {
+    let base_path = CString::new(MOUNT_POINT)?;
+    let partition_label = CString::new(PARTITION_LABEL)?;
+
+    let mount_config = esp_vfs_fat_mount_config_t {
+        format_if_mount_failed: true,
+        max_files: 4,
+        allocation_unit_size: 0,
+        #[cfg(esp_idf_version_major = "5")]
+        disk_status_check_enable: false,
+    };
+
+    let mut wl_handle: wl_handle_t = 0;
+    let err = unsafe {
+        esp_vfs_fat_spiflash_mount_rw_wl(
+            base_path.as_ptr(),
+            partition_label.as_ptr(),
+            &mount_config,
+            &mut wl_handle,
+        )
+    };
+
+    if err != ESP_OK {
+        bail!("Failed to mount {} partition: {}", PARTITION_LABEL, err);
+    }
+
+    info!("Mounted {} partition at {}", PARTITION_LABEL, MOUNT_POINT);
+    Ok(())
+}
+
+/// Appends a measurement to the ring-buffer file, dropping the oldest
+/// record if the buffer is at capacity.
+pub fn append(measurement: &MqttMeasurement) -> Result<()> {
+    let mut records = read_all().unwrap_or_default();
+    records.push(measurement.clone());
+    if records.len() > MAX_BUFFERED_RECORDS {
+        let overflow = records.len() - MAX_BUFFERED_RECORDS;
+        records.drain(0..overflow);
+    }
+
+    write_all(&records)
+}
+
+/// Drains every buffered record, oldest first, publishing it via `publish`.
+///
+/// Each record is removed from the buffer file as soon as it is published
+/// successfully, so a failure partway through a replay (e.g. the connection
+/// drops again) leaves only the records that were never sent, instead of
+/// re-sending everything on the next attempt.
+pub fn drain(mut publish: impl FnMut(&MqttMeasurement) -> Result<()>) -> Result<()> {
+    let mut records = read_all()?;
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    info!("Replaying {} buffered measurement(s)", records.len());
+    while !records.is_empty() {
+        if let Err(e) = publish(&records[0]) {
+            write_all(&records)?;
+            return Err(e);
+        }
+        records.remove(0);
+        write_all(&records)?;
+    }
+
+    Ok(())
+}
+
+fn write_all(records: &[MqttMeasurement]) -> Result<()> {
+    if records.is_empty() {
+        std::fs::remove_file(BUFFER_PATH).ok();
+        return Ok(());
+    }
+
+    let mut file = File::create(BUFFER_PATH)?;
+    for record in records {
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+    }
+
+    Ok(())
+}
+
+fn read_all() -> Result<Vec<MqttMeasurement>> {
+    let file = match OpenOptions::new().read(true).open(BUFFER_PATH) {
+        Ok(file) => file,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Skipping corrupt buffered record: {}", e),
+        }
+    }
+
+    Ok(records)
+}
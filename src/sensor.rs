@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Result};
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use esp_idf_hal::delay::FreeRtos;
+use std::fmt::Debug;
+
+use crate::command::PowerMode;
+
+/// A single reading from an environmental sensor. Fields the sensor cannot
+/// provide are left `None` (e.g. `co2` on a plain temperature/humidity part).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Reading {
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub co2: Option<f32>,
+}
+
+/// Common interface for the boards' I2C environmental sensors, so `main` can
+/// sample whichever one is selected by `Config::sensor` without caring which.
+pub trait EnvSensor {
+    /// Triggers a measurement cycle for the given power mode and blocks until
+    /// its result is ready to be read.
+    fn start_measurement(&mut self, power_mode: PowerMode) -> Result<()>;
+    /// Returns the result of the measurement triggered by `start_measurement`.
+    fn read(&mut self) -> Result<Reading>;
+    /// Whether `start_measurement` already blocks for this sensor's own fixed
+    /// sampling period. When `true`, callers should skip any additional
+    /// `Config::sample_interval_ms` delay, since this sensor doesn't have a
+    /// faster cadence to throttle down to.
+    fn self_paced(&self) -> bool {
+        false
+    }
+}
+
+/// SHTC3 temperature/humidity sensor, as used on the ESP32-C3-DevKitM Rust board.
+pub struct Shtc3Sensor<I2C> {
+    device: shtcx::Shtc3<I2C>,
+}
+
+impl<I2C, E> Shtc3Sensor<I2C>
+where
+    I2C: Write<Error = E> + Read<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Self {
+            device: shtcx::shtc3(i2c),
+        }
+    }
+}
+
+impl<I2C, E> EnvSensor for Shtc3Sensor<I2C>
+where
+    I2C: Write<Error = E> + Read<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    fn start_measurement(&mut self, power_mode: PowerMode) -> Result<()> {
+        let mode = match power_mode {
+            PowerMode::Normal => shtcx::PowerMode::NormalMode,
+            PowerMode::LowPower => shtcx::PowerMode::LowPower,
+        };
+        self.device
+            .start_measurement(mode)
+            .map_err(|e| anyhow!("SHTC3 start_measurement failed: {:?}", e))?;
+        FreeRtos.delay_ms(100u32);
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Reading> {
+        let measurement = self
+            .device
+            .get_measurement_result()
+            .map_err(|e| anyhow!("SHTC3 read failed: {:?}", e))?;
+        Ok(Reading {
+            temperature: Some(measurement.temperature.as_degrees_celsius()),
+            humidity: Some(measurement.humidity.as_percent()),
+            co2: None,
+        })
+    }
+}
+
+/// SCD41 CO2/temperature/humidity sensor, sharing the same I2C bus as the SHTC3.
+pub struct Scd41Sensor<I2C> {
+    device: scd4x::Scd4x<I2C, FreeRtos>,
+}
+
+impl<I2C, E> Scd41Sensor<I2C>
+where
+    I2C: Write<Error = E> + Read<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C) -> Result<Self> {
+        let mut device = scd4x::Scd4x::new(i2c, FreeRtos);
+        device
+            .start_periodic_measurement()
+            .map_err(|e| anyhow!("SCD41 start_periodic_measurement failed: {:?}", e))?;
+        Ok(Self { device })
+    }
+}
+
+impl<I2C, E> EnvSensor for Scd41Sensor<I2C>
+where
+    I2C: Write<Error = E> + Read<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    fn start_measurement(&mut self, _power_mode: PowerMode) -> Result<()> {
+        // The SCD41 free-runs once periodic measurement is started; just wait
+        // for the next 5s sample to become ready.
+        FreeRtos.delay_ms(5_000u32);
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Reading> {
+        let measurement = self
+            .device
+            .measurement()
+            .map_err(|e| anyhow!("SCD41 read failed: {:?}", e))?;
+        Ok(Reading {
+            temperature: Some(measurement.temperature),
+            humidity: Some(measurement.humidity),
+            co2: Some(measurement.co2 as f32),
+        })
+    }
+
+    fn self_paced(&self) -> bool {
+        // The SCD41's own 5s measurement period is already far slower than
+        // any reasonable `sample_interval_ms`, so don't sleep again on top of it.
+        true
+    }
+}